@@ -1,6 +1,7 @@
 // See LICENSE file for copyright and license details.
 
 use std;
+use std::collections::HashMap;
 use glfw;
 use gl;
 use gl::types::{
@@ -10,6 +11,7 @@ use gl::types::{
     GLenum,
     GLsizeiptr,
     GLsizei,
+    GLfloat,
 };
 use cgmath::matrix::{
     Matrix,
@@ -126,6 +128,23 @@ pub fn draw_mesh(faces_count: Int) {
     gl::DrawArrays(gl::TRIANGLES, starting_index, vertices_count);
 }
 
+// Draws a full-screen triangle with no bound vertex buffer; the vertex
+// shader derives its position from `gl_VertexID` (see fxaa.vs.glsl).
+pub fn draw_fullscreen_triangle() {
+    gl::DrawArrays(gl::TRIANGLES, 0, 3);
+}
+
+pub fn draw_mesh_indexed(indices_count: Int) {
+    unsafe {
+        gl::DrawElements(
+            gl::TRIANGLES,
+            indices_count,
+            gl::UNSIGNED_INT,
+            std::ptr::null(),
+        );
+    }
+}
+
 pub fn uniform_mat4f(mat_id: MatId, mat: &Mat4<Float>) {
     unsafe {
         let MatId(id) = mat_id;
@@ -168,30 +187,35 @@ pub fn delete_buffer(buffer: &VboId) {
     }
 }
 
-fn fill_buffer<T>(buffer_size: i64, data: &[T]) {
+fn fill_buffer<T>(target: GLenum, buffer_size: i64, data: &[T]) {
     unsafe {
         let data_ptr = std::cast::transmute(&data[0]);
-        gl::BufferData(
-            gl::ARRAY_BUFFER, buffer_size, data_ptr, gl::STATIC_DRAW);
+        gl::BufferData(target, buffer_size, data_ptr, gl::STATIC_DRAW);
     }
 }
 
 pub fn fill_current_coord_vbo(data: &[VertexCoord]) {
     let size = std::mem::size_of::<VertexCoord>();
     let buffer_size = (data.len() * size) as GLsizeiptr;
-    fill_buffer(buffer_size, data);
+    fill_buffer(gl::ARRAY_BUFFER, buffer_size, data);
 }
 
 pub fn fill_current_color_vbo(data: &[Color3]) {
     let size = std::mem::size_of::<Color3>();
     let buffer_size = (data.len() * size) as GLsizeiptr;
-    fill_buffer(buffer_size, data);
+    fill_buffer(gl::ARRAY_BUFFER, buffer_size, data);
 }
 
 pub fn fill_current_texture_coords_vbo(data: &[TextureCoord]) {
     let size = std::mem::size_of::<TextureCoord>();
     let buffer_size = (data.len() * size) as GLsizeiptr;
-    fill_buffer(buffer_size, data);
+    fill_buffer(gl::ARRAY_BUFFER, buffer_size, data);
+}
+
+pub fn fill_current_index_buffer(data: &[u32]) {
+    let size = std::mem::size_of::<u32>();
+    let buffer_size = (data.len() * size) as GLsizeiptr;
+    fill_buffer(gl::ELEMENT_ARRAY_BUFFER, buffer_size, data);
 }
 
 pub fn vertex_attrib_pointer(attr: AttrId, components_count: Int) {
@@ -221,10 +245,45 @@ pub fn enable_vertex_attrib_array(attr: &AttrId) {
     gl::EnableVertexAttribArray(id);
 }
 
+// A handful of notification-severity ids that fire constantly on driver
+// state changes we already expect (buffer usage hints, shader
+// recompiles) and would otherwise drown out real problems.
+static NOISY_DEBUG_IDS: &'static [GLuint] = &[
+    131185, // buffer detailed info (usage/memory placement hint)
+    131218, // shader will be recompiled due to GL state mismatches
+];
+
+extern "system" fn debug_callback(
+    source: GLenum,
+    type_: GLenum,
+    id: GLuint,
+    severity: GLenum,
+    _length: GLsizei,
+    message: *GLchar,
+    _user_param: *mut std::libc::c_void,
+) {
+    if NOISY_DEBUG_IDS.contains(&id) {
+        return;
+    }
+    let text = unsafe { std::str::raw::from_c_str(message) };
+    println!("gl debug: source={} type={} id={} severity={}: {}",
+        source, type_, id, severity, text);
+    if severity == gl::DEBUG_SEVERITY_HIGH {
+        fail!("GL error (severity HIGH, id {}): {}", id, text);
+    }
+}
+
 pub fn init_opengl() {
     // TODO: Remove 'use glfw, glfw::...'?
     gl::load_with(glfw::get_proc_address);
     gl::Enable(gl::DEPTH_TEST);
+    if gl::DebugMessageCallback::is_loaded() {
+        gl::Enable(gl::DEBUG_OUTPUT);
+        gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+        unsafe {
+            gl::DebugMessageCallback(debug_callback, std::ptr::null());
+        }
+    }
 }
 
 // TODO: Drop
@@ -245,19 +304,189 @@ pub fn viewport(size: Size2<Int>) {
     gl::Viewport(0, 0, size.w, size.h);
 }
 
+/// Sets a `vec2` uniform from a `Size2`. Shared by anything that feeds a
+/// shader resolution-dependent uniforms (e.g. a post-process pass's
+/// `source_size`/`output_size`).
+pub fn set_size_uniform(shader: &ShaderId, name: &str, size: Size2<Int>) {
+    let loc = get_uniform(shader, name) as GLint;
+    gl::Uniform2f(loc, size.w as GLfloat, size.h as GLfloat);
+}
+
 pub fn bind_buffer(buffer: &VboId) {
     let VboId(id) = *buffer;
     gl::BindBuffer(gl::ARRAY_BUFFER, id);
 }
 
 pub fn enable_texture(shader: &ShaderId, texture: &TextureId) {
+    enable_texture_as(shader, texture, "basic_texture");
+}
+
+/// Like `enable_texture`, but binds the sampler uniform under `uniform_name`
+/// instead of assuming the regular mesh shaders' `"basic_texture"` name --
+/// for shaders (e.g. post-process passes) that sample their input under a
+/// different name.
+pub fn enable_texture_as(shader: &ShaderId, texture: &TextureId, uniform_name: &str) {
     let TextureId(id) = *texture;
-    let basic_texture_loc = get_uniform(shader, "basic_texture") as GLint;
-    gl::Uniform1ui(basic_texture_loc, 0);
+    let loc = get_uniform(shader, uniform_name) as GLint;
+    gl::Uniform1ui(loc, 0);
     gl::ActiveTexture(gl::TEXTURE0);
     gl::BindTexture(gl::TEXTURE_2D, id);
 }
 
+pub fn gen_framebuffer() -> GLuint {
+    let mut fbo = 0;
+    unsafe {
+        gl::GenFramebuffers(1, &mut fbo);
+    }
+    fbo
+}
+
+pub fn bind_framebuffer(fbo: GLuint) {
+    gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+}
+
+pub fn bind_default_framebuffer() {
+    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+}
+
+/// Texture wrap mode for a `Framebuffer`'s color attachment.
+#[deriving(Clone)]
+pub enum WrapMode {
+    Repeat,
+    ClampToEdge,
+}
+
+fn wrap_mode_to_gl(wrap_mode: WrapMode) -> GLint {
+    match wrap_mode {
+        Repeat => gl::REPEAT as GLint,
+        ClampToEdge => gl::CLAMP_TO_EDGE as GLint,
+    }
+}
+
+/// An offscreen render target: a color texture plus a depth renderbuffer,
+/// both sized to `Context::win_size`. Used to render the scene before
+/// running a full-screen post-process pass (e.g. FXAA) over it.
+pub struct Framebuffer {
+    fbo: GLuint,
+    color_texture: TextureId,
+    depth_renderbuffer: GLuint,
+    size: Size2<Int>,
+    wrap_mode: WrapMode,
+}
+
+impl Framebuffer {
+    pub fn new(size: Size2<Int>, wrap_mode: WrapMode) -> Framebuffer {
+        let mut framebuffer = Framebuffer {
+            fbo: 0,
+            color_texture: TextureId(0),
+            depth_renderbuffer: 0,
+            size: size,
+            wrap_mode: wrap_mode,
+        };
+        framebuffer.allocate();
+        framebuffer
+    }
+
+    fn allocate(&mut self) {
+        self.fbo = gen_framebuffer();
+        bind_framebuffer(self.fbo);
+        unsafe {
+            let mut texture_id = 0;
+            gl::GenTextures(1, &mut texture_id);
+            gl::BindTexture(gl::TEXTURE_2D, texture_id);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA as GLint,
+                self.size.w as GLsizei,
+                self.size.h as GLsizei,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                std::ptr::null(),
+            );
+            gl::TexParameteri(gl::TEXTURE_2D,
+                gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D,
+                gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            let wrap = wrap_mode_to_gl(self.wrap_mode.clone());
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, texture_id, 0);
+            self.color_texture = TextureId(texture_id);
+
+            let mut rbo = 0;
+            gl::GenRenderbuffers(1, &mut rbo);
+            gl::BindRenderbuffer(gl::RENDERBUFFER, rbo);
+            gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT24,
+                self.size.w as GLsizei, self.size.h as GLsizei);
+            gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, rbo);
+            self.depth_renderbuffer = rbo;
+
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            if status != gl::FRAMEBUFFER_COMPLETE {
+                fail!("Framebuffer is not complete: {}", status);
+            }
+        }
+        bind_default_framebuffer();
+    }
+
+    fn deallocate(&self) {
+        unsafe {
+            let TextureId(texture_id) = self.color_texture;
+            gl::DeleteTextures(1, &texture_id);
+            gl::DeleteRenderbuffers(1, &self.depth_renderbuffer);
+            gl::DeleteFramebuffers(1, &self.fbo);
+        }
+    }
+
+    /// Drops and recreates the attachments at a new size. Called in
+    /// response to a `SizeEvent` so the offscreen target always matches
+    /// the window.
+    pub fn resize(&mut self, size: Size2<Int>) {
+        self.deallocate();
+        self.size = size;
+        self.allocate();
+    }
+
+    pub fn color_texture(&self) -> &TextureId {
+        &self.color_texture
+    }
+
+    pub fn size(&self) -> Size2<Int> {
+        self.size
+    }
+
+    pub fn bind(&self) {
+        bind_framebuffer(self.fbo);
+    }
+
+    /// Copies this framebuffer's color attachment straight to the
+    /// default framebuffer, with no post-processing. Used to skip the
+    /// FXAA resolve pass (e.g. `Context::fxaa_enabled == false`) while
+    /// still presenting what was rendered into this offscreen target.
+    pub fn blit_to_default(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+            gl::BlitFramebuffer(
+                0, 0, self.size.w as GLint, self.size.h as GLint,
+                0, 0, self.size.w as GLint, self.size.h as GLint,
+                gl::COLOR_BUFFER_BIT, gl::NEAREST,
+            );
+        }
+        bind_default_framebuffer();
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        self.deallocate();
+    }
+}
+
 fn load_image(path: ~str) -> image::Image<u8> {
     let load_result = image::load(path);
     match load_result {
@@ -312,4 +541,121 @@ pub fn load_texture(path: ~str) -> TextureId {
     TextureId(id)
 }
 
+pub struct QueryId(pub GLuint);
+
+pub fn gen_query() -> QueryId {
+    let mut id = 0;
+    unsafe {
+        gl::GenQueries(1, &mut id);
+    }
+    QueryId(id)
+}
+
+pub fn delete_query(query: &QueryId) {
+    unsafe {
+        let QueryId(id) = *query;
+        gl::DeleteQueries(1, &id);
+    }
+}
+
+// A scope's pair of query objects, one per frame-parity, so a scope's
+// result can always be read back a full frame after it was recorded.
+struct ScopeQueries {
+    queries: [QueryId, ..2],
+    has_result: [bool, ..2],
+}
+
+/// Collects per-scope GPU timings via `GL_TIME_ELAPSED` queries. Each
+/// named scope gets a two-frame ring of query objects: frame N's
+/// `begin`/`end` records into `queries[N % 2]`, while `collect_and_advance`
+/// reads back `queries[(N + 1) % 2]`, i.e. the previous frame's result,
+/// so the readback never stalls the pipeline waiting on the GPU.
+pub struct Profiler {
+    scopes: HashMap<~str, ScopeQueries>,
+    frame: uint,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            scopes: HashMap::new(),
+            frame: 0,
+        }
+    }
+
+    fn scope<'a>(&'a mut self, name: &str) -> &'a mut ScopeQueries {
+        if !self.scopes.contains_key(&name.to_owned()) {
+            self.scopes.insert(name.to_owned(), ScopeQueries {
+                queries: [gen_query(), gen_query()],
+                has_result: [false, false],
+            });
+        }
+        self.scopes.get_mut(&name.to_owned())
+    }
+
+    pub fn begin(&mut self, name: &str) {
+        let frame = self.frame;
+        let scope = self.scope(name);
+        let QueryId(id) = scope.queries[frame];
+        unsafe {
+            gl::BeginQuery(gl::TIME_ELAPSED, id);
+        }
+    }
+
+    pub fn end(&self) {
+        unsafe {
+            gl::EndQuery(gl::TIME_ELAPSED);
+        }
+    }
+
+    /// Reads back every scope's previous-frame timing (in milliseconds)
+    /// and flips the ring for the next frame.
+    pub fn collect_and_advance(&mut self) -> Vec<(~str, f64)> {
+        let readback_frame = (self.frame + 1) % 2;
+        let mut results = Vec::new();
+        for (name, scope) in self.scopes.mut_iter() {
+            if scope.has_result[readback_frame] {
+                let QueryId(id) = scope.queries[readback_frame];
+                let mut ns: u64 = 0;
+                unsafe {
+                    gl::GetQueryObjectui64v(id, gl::QUERY_RESULT, &mut ns);
+                }
+                results.push((name.clone(), ns as f64 / 1_000_000.0));
+            }
+            scope.has_result[readback_frame] = true;
+        }
+        self.frame = readback_frame;
+        results
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        for (_, scope) in self.scopes.mut_iter() {
+            delete_query(&scope.queries[0]);
+            delete_query(&scope.queries[1]);
+        }
+    }
+}
+
+/// RAII guard that times the GPU work done while it's alive and records
+/// it into `name`'s scope of the given `Profiler`.
+pub struct TimeScope<'a> {
+    profiler: &'a mut Profiler,
+}
+
+impl<'a> TimeScope<'a> {
+    pub fn new<'a>(profiler: &'a mut Profiler, name: &str) -> TimeScope<'a> {
+        profiler.begin(name);
+        TimeScope { profiler: profiler }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for TimeScope<'a> {
+    fn drop(&mut self) {
+        self.profiler.end();
+    }
+}
+
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
@@ -5,13 +5,15 @@ use gl::types::{
   GLfloat,
   GLuint,
 };
-use cgmath::vector::Vec3;
+use cgmath::vector::{Vec2, Vec3};
 use glh = gl_helpers;
 use color::Color3;
 
 pub struct Mesh {
   vbo: GLuint,
   color_vbo: Option<GLuint>,
+  texture_coords_vbo: Option<GLuint>,
+  ebo: Option<GLuint>,
   len: int,
 }
 
@@ -20,6 +22,8 @@ impl Mesh {
     Mesh {
       vbo: 0,
       color_vbo: None,
+      texture_coords_vbo: None,
+      ebo: None,
       len: 0,
     }
   }
@@ -31,6 +35,19 @@ impl Mesh {
     glh::fill_current_coord_vbo(data);
   }
 
+  // Uploads a deduplicated vertex list plus an index list, so a shared
+  // vertex is stored (and transformed) only once.
+  pub fn init_indexed(&mut self, data: &[Vec3<GLfloat>], indices: &[u32]) {
+    self.len = indices.len() as int;
+    self.vbo = glh::gen_buffer();
+    gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+    glh::fill_current_coord_vbo(data);
+    let ebo = glh::gen_buffer();
+    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
+    glh::fill_current_index_buffer(indices);
+    self.ebo = Some(ebo);
+  }
+
   pub fn set_color(&mut self, data: &[Color3]) {
     self.len = data.len() as int;
     self.color_vbo = Some(glh::gen_buffer());
@@ -38,20 +55,44 @@ impl Mesh {
     glh::fill_current_color_vbo(data);
   }
 
+  pub fn set_texture_coords(&mut self, data: &[Vec2<GLfloat>]) {
+    self.texture_coords_vbo = Some(glh::gen_buffer());
+    gl::BindBuffer(gl::ARRAY_BUFFER, self.texture_coords_vbo.unwrap());
+    glh::fill_current_texture_coords_vbo(data);
+  }
+
   pub fn draw(&self, program: GLuint) {
     if !self.color_vbo.is_none() {
       gl::BindBuffer(gl::ARRAY_BUFFER, self.color_vbo.unwrap());
       glh::vertex_attrib_pointer(glh::get_attr(program, "color"));
     }
+    if !self.texture_coords_vbo.is_none() {
+      gl::BindBuffer(gl::ARRAY_BUFFER, self.texture_coords_vbo.unwrap());
+      glh::vertex_attrib_pointer(glh::get_attr(program, "texture_coordinates"));
+    }
     gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
     glh::vertex_attrib_pointer(glh::get_attr(program, "position"));
-    glh::draw_mesh(self.len);
+    match self.ebo {
+      Some(ebo) => {
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
+        glh::draw_mesh_indexed(self.len);
+      },
+      None => glh::draw_mesh(self.len),
+    }
   }
 }
 
 impl Drop for Mesh {
   fn drop(&mut self) {
     glh::delete_buffer(self.vbo);
+    match self.ebo {
+      Some(ebo) => glh::delete_buffer(ebo),
+      None => {},
+    }
+    match self.texture_coords_vbo {
+      Some(vbo) => glh::delete_buffer(vbo),
+      None => {},
+    }
   }
 }
 
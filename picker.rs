@@ -1,6 +1,7 @@
 // See LICENSE file for copyright and license details.
 
 use std;
+use std::collections::HashMap;
 use cgmath::vector::{
     Vec3,
     Vec2,
@@ -15,6 +16,7 @@ use core_types::{
     Int,
     Size2,
     MapPos,
+    UnitId,
 };
 use gl_types::{
     VertexCoord,
@@ -24,20 +26,46 @@ use gl_types::{
     MatId,
 };
 
+/// Anything the mouse can click on. Looked up from the 24-bit id baked
+/// into a pixel's color by the picking draw pass.
+#[deriving(Clone)]
+pub enum Pickable {
+    PickableTile(MapPos),
+    PickableUnit(UnitId),
+}
+
+// Splits a non-zero picking id across the RGB channels:
+// `r = id & 0xFF`, `g = (id >> 8) & 0xFF`, `b = (id >> 16) & 0xFF`.
+// Id 0 is reserved for "nothing".
+fn id_to_color(id: u32) -> Color3 {
+    Color3 {
+        r: (id & 0xFF) as Float / 255.0,
+        g: ((id >> 8) & 0xFF) as Float / 255.0,
+        b: ((id >> 16) & 0xFF) as Float / 255.0,
+    }
+}
+
+fn color_to_id(r: u8, g: u8, b: u8) -> u32 {
+    r as u32 | (g as u32 << 8) | (b as u32 << 16)
+}
+
 fn build_hex_map_mesh(
     geom: &Geom,
-    map_size: Size2<Int>
+    map_size: Size2<Int>,
+    registry: &mut HashMap<u32, Pickable>,
+    next_id: &mut u32,
 ) -> (~[VertexCoord], ~[Color3]) {
     let mut c_data = ~[];
     let mut v_data = ~[];
     for tile_pos in MapPosIter::new(map_size) {
+        *next_id += 1;
+        let id = *next_id;
+        registry.insert(id, PickableTile(tile_pos));
+        let color = id_to_color(id);
         let pos3d = geom.map_pos_to_world_pos(tile_pos);
         for num in range(0 as Int, 6) {
             let vertex = geom.index_to_hex_vertex(num);
             let next_vertex = geom.index_to_hex_vertex(num + 1);
-            let col_x = tile_pos.x as Float / 255.0;
-            let col_y = tile_pos.y as Float / 255.0;
-            let color = Color3{r: col_x, g: col_y, b: 1.0};
             v_data.push(pos3d + vertex);
             c_data.push(color);
             v_data.push(pos3d + next_vertex);
@@ -49,22 +77,31 @@ fn build_hex_map_mesh(
     (v_data, c_data)
 }
 
-pub struct TilePicker {
+/// Renders pickable geometry into an offscreen color buffer with each
+/// object's id baked into its color, then reads back a single pixel
+/// under the mouse to tell what, if anything, is there. Generalizes the
+/// old tile-only picker so units, buildings, etc. can share one picking
+/// pass, and lifts the 255x255 map-size limit that came from encoding
+/// `tile_pos.x`/`tile_pos.y` directly into two color channels.
+pub struct Picker {
     program: ShaderId,
     map_mesh: Mesh,
     mat_id: MatId,
     win_size: Size2<Int>,
+    registry: HashMap<u32, Pickable>,
+    next_id: u32,
 }
 
-impl TilePicker {
-    pub fn new(win_size: Size2<Int>) -> TilePicker {
-        let picker = TilePicker {
+impl Picker {
+    pub fn new(win_size: Size2<Int>) -> Picker {
+        Picker {
             program: ShaderId(0),
             map_mesh: Mesh::new(),
             mat_id: MatId(0),
             win_size: win_size,
-        };
-        picker
+            registry: HashMap::new(),
+            next_id: 0,
+        }
     }
 
     pub fn set_win_size(&mut self, win_size: Size2<Int>) {
@@ -75,6 +112,13 @@ impl TilePicker {
         glh::delete_program(&self.program);
     }
 
+    /// (Re)builds the pickable tile geometry for `map_size`. Any units
+    /// already registered via `register_unit` keep both their id and their
+    /// assigned color: this only drops and reallocates the `PickableTile`
+    /// entries, and hands out new tile ids starting after `next_id`
+    /// rather than resetting it to 0, so a unit's geometry (already
+    /// painted with its id color before `init` runs) still resolves to
+    /// the right unit afterwards.
     pub fn init(&mut self, geom: &Geom, map_size: Size2<Int>) {
         self.program = glh::compile_program(
             read_file(&Path::new("pick.vs.glsl")),
@@ -88,16 +132,37 @@ impl TilePicker {
         glh::enable_vertex_attrib_array(&color_attr);
         glh::vertex_attrib_pointer(position_attr, 3);
         glh::vertex_attrib_pointer(color_attr, 3);
-        let (vertex_data, color_data) = build_hex_map_mesh(geom, map_size);
+        let ids_to_drop: ~[u32] = self.registry.iter()
+            .filter(|&(_, pickable)| match *pickable {
+                PickableTile(_) => true,
+                PickableUnit(_) => false,
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids_to_drop.iter() {
+            self.registry.remove(id);
+        }
+        let (vertex_data, color_data) = build_hex_map_mesh(
+            geom, map_size, &mut self.registry, &mut self.next_id);
         self.map_mesh.set_vertex_coords(vertex_data);
         self.map_mesh.set_color(color_data);
         self.mat_id = MatId(glh::get_uniform(&self.program, "mvp_mat"));
     }
 
+    /// Registers a unit as pickable and returns the color its geometry
+    /// must be painted with in the picking pass. Can be called either
+    /// before or after `init`: unit ids are never reclaimed by `init`.
+    pub fn register_unit(&mut self, unit_id: UnitId) -> Color3 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.registry.insert(id, PickableUnit(unit_id));
+        id_to_color(id)
+    }
+
     fn read_coords_from_image_buffer(
         &self,
         mouse_pos: Vec2<Int>
-    ) -> Option<MapPos> {
+    ) -> Option<Pickable> {
         use gl; // TODO: remove
         let height = self.win_size.h;
         let reverted_y = height - mouse_pos.y;
@@ -111,18 +176,19 @@ impl TilePicker {
                 data_ptr
             );
         }
-        if data[2] != 0 {
-            Some(Vec2{x: data[0] as Int, y: data[1] as Int})
-        } else {
+        let id = color_to_id(data[0], data[1], data[2]);
+        if id == 0 {
             None
+        } else {
+            self.registry.find(&id).map(|pickable| pickable.clone())
         }
     }
 
-    pub fn pick_tile(
+    pub fn pick(
         &mut self,
         camera: &Camera,
         mouse_pos: Vec2<Int>
-    ) -> Option<MapPos> {
+    ) -> Option<Pickable> {
         glh::use_program(&self.program);
         glh::uniform_mat4f(self.mat_id, &camera.mat());
         glh::set_clear_color(0.0, 0.0, 0.0);
@@ -132,7 +198,7 @@ impl TilePicker {
     }
 }
 
-impl Drop for TilePicker {
+impl Drop for Picker {
     fn drop(&mut self) {
         self.cleanup_opengl();
     }
@@ -0,0 +1,218 @@
+// See LICENSE file for copyright and license details.
+
+use std::io::{BufferedReader, File};
+use gl;
+use gl::types::GLint;
+use glh = gl_helpers;
+use misc::read_file;
+use gl_types::{
+    ShaderId,
+    TextureId,
+};
+use core_types::{
+    Int,
+    Size2,
+};
+
+/// How a pass's render target is sized relative to the window.
+#[deriving(Clone)]
+pub enum ScaleMode {
+    /// Same resolution as the pass's input texture.
+    SourceScale,
+    /// Window resolution.
+    ViewportScale,
+    /// A fixed, pass-specific resolution.
+    AbsoluteScale(Int, Int),
+}
+
+fn parse_scale_mode(word: &str) -> ScaleMode {
+    if word == "source" {
+        SourceScale
+    } else if word == "viewport" {
+        ViewportScale
+    } else if word.starts_with("absolute:") {
+        let dims = word.slice_from("absolute:".len());
+        let mut parts = dims.split('x');
+        let w: Int = from_str(parts.next().expect("absolute scale width"))
+            .expect("absolute scale width is not a number");
+        let h: Int = from_str(parts.next().expect("absolute scale height"))
+            .expect("absolute scale height is not a number");
+        AbsoluteScale(w, h)
+    } else {
+        fail!("Unknown scale mode: '{}'", word);
+    }
+}
+
+fn parse_wrap_mode(word: &str) -> glh::WrapMode {
+    match word {
+        "repeat" => glh::Repeat,
+        "clamp_to_edge" => glh::ClampToEdge,
+        _ => fail!("Unknown wrap mode: '{}'", word),
+    }
+}
+
+/// One line of a pipeline preset file: a shader pair, the resolution its
+/// output is rendered at, and how its output texture should be sampled.
+pub struct PassPreset {
+    pub vertex_shader_path: ~str,
+    pub fragment_shader_path: ~str,
+    pub scale_mode: ScaleMode,
+    pub wrap_mode: glh::WrapMode,
+}
+
+/// Reads an ordered list of post-process passes out of a preset file.
+/// Each non-empty, non-comment line is:
+///
+///   pass <vertex_shader> <fragment_shader> <source|viewport|absolute:WxH> <repeat|clamp_to_edge>
+pub fn load_preset(path: &Path) -> Vec<PassPreset> {
+    let mut file = BufferedReader::new(File::open(path));
+    let mut passes = Vec::new();
+    for line in file.lines() {
+        let line = line.unwrap_or_else(|msg| fail!("Pipeline preset: read error: {}", msg));
+        let line = line.as_slice().trim();
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+        let mut words = line.words();
+        match words.next() {
+            Some("pass") => {
+                let vertex_shader_path = words.next()
+                    .expect("pass is missing a vertex shader path").to_owned();
+                let fragment_shader_path = words.next()
+                    .expect("pass is missing a fragment shader path").to_owned();
+                let scale_mode = parse_scale_mode(
+                    words.next().expect("pass is missing a scale mode"));
+                let wrap_mode = parse_wrap_mode(
+                    words.next().expect("pass is missing a wrap mode"));
+                passes.push(PassPreset {
+                    vertex_shader_path: vertex_shader_path,
+                    fragment_shader_path: fragment_shader_path,
+                    scale_mode: scale_mode,
+                    wrap_mode: wrap_mode,
+                });
+            },
+            _ => {},
+        }
+    }
+    passes
+}
+
+struct Pass {
+    program: ShaderId,
+    scale_mode: ScaleMode,
+    // `None` for the final pass: it renders straight to the default
+    // framebuffer instead of an offscreen texture.
+    framebuffer: Option<glh::Framebuffer>,
+}
+
+fn resolve_size(scale_mode: &ScaleMode, source_size: Size2<Int>, win_size: Size2<Int>) -> Size2<Int> {
+    match *scale_mode {
+        SourceScale => source_size,
+        ViewportScale => win_size,
+        AbsoluteScale(w, h) => Size2{w: w, h: h},
+    }
+}
+
+/// A data-driven, multi-pass post-processing stack: pass N samples pass
+/// N-1's output texture (exposed to its fragment shader as the `source`
+/// sampler uniform, with `source_size`/`output_size` telling it the
+/// resolutions involved), and the final pass renders to the screen.
+pub struct Pipeline {
+    passes: Vec<Pass>,
+    win_size: Size2<Int>,
+}
+
+impl Pipeline {
+    pub fn new(presets: &[PassPreset], win_size: Size2<Int>) -> Pipeline {
+        let last_index = presets.len() - 1;
+        let mut passes = Vec::new();
+        let mut previous_size = win_size;
+        for (i, preset) in presets.iter().enumerate() {
+            let program = glh::compile_program(
+                read_file(&Path::new(preset.vertex_shader_path.clone())),
+                read_file(&Path::new(preset.fragment_shader_path.clone())),
+            );
+            let size = resolve_size(&preset.scale_mode, previous_size, win_size);
+            let framebuffer = if i == last_index {
+                None
+            } else {
+                Some(glh::Framebuffer::new(size, preset.wrap_mode.clone()))
+            };
+            previous_size = size;
+            passes.push(Pass {
+                program: program,
+                scale_mode: preset.scale_mode.clone(),
+                framebuffer: framebuffer,
+            });
+        }
+        Pipeline {
+            passes: passes,
+            win_size: win_size,
+        }
+    }
+
+    pub fn set_win_size(&mut self, win_size: Size2<Int>) {
+        self.win_size = win_size;
+        let mut previous_size = win_size;
+        for pass in self.passes.mut_iter() {
+            let size = resolve_size(&pass.scale_mode, previous_size, win_size);
+            match pass.framebuffer {
+                Some(ref mut framebuffer) => framebuffer.resize(size),
+                None => {},
+            }
+            previous_size = size;
+        }
+    }
+
+    /// Runs the whole pipeline for one frame. `render_scene` draws the
+    /// actual game content into the first pass's target.
+    pub fn run(&self, render_scene: |&ShaderId|) {
+        let mut source: Option<TextureId> = None;
+        let mut source_size = self.win_size;
+        for (i, pass) in self.passes.iter().enumerate() {
+            match pass.framebuffer {
+                Some(ref framebuffer) => framebuffer.bind(),
+                None => glh::bind_default_framebuffer(),
+            }
+            glh::use_program(&pass.program);
+            if i == 0 {
+                render_scene(&pass.program);
+            } else {
+                let source_texture = source.expect("pass after the first needs a source texture");
+                bind_source_texture(&pass.program, &source_texture);
+                let output_size = match pass.framebuffer {
+                    Some(ref framebuffer) => framebuffer.size(),
+                    None => self.win_size,
+                };
+                glh::set_size_uniform(&pass.program, "source_size", source_size);
+                glh::set_size_uniform(&pass.program, "output_size", output_size);
+                glh::draw_fullscreen_triangle();
+            }
+            source = match pass.framebuffer {
+                Some(ref framebuffer) => {
+                    let &TextureId(id) = framebuffer.color_texture();
+                    Some(TextureId(id))
+                },
+                None => None,
+            };
+            source_size = match pass.framebuffer {
+                Some(ref framebuffer) => framebuffer.size(),
+                None => self.win_size,
+            };
+        }
+    }
+}
+
+// `glh::enable_texture` hardcodes the "basic_texture" uniform name used
+// by the regular mesh shaders; pipeline passes sample their input as
+// `source` instead (see fxaa.fs.glsl), so bind it directly rather than
+// going through that helper.
+fn bind_source_texture(program: &ShaderId, texture: &TextureId) {
+    let &TextureId(texture_id) = texture;
+    let source_loc = glh::get_uniform(program, "source") as GLint;
+    gl::Uniform1i(source_loc, 0);
+    gl::ActiveTexture(gl::TEXTURE0);
+    gl::BindTexture(gl::TEXTURE_2D, texture_id);
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
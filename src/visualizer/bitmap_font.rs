@@ -0,0 +1,141 @@
+// See LICENSE file for copyright and license details.
+
+use std::io::File;
+use std::collections::HashMap;
+use serialize::json;
+use cgmath::vector::{Vector2, Vector3};
+use core::types::Point2;
+use visualizer::types::{MFloat, VertexCoord, TextureCoord};
+use gl_helpers::{load_texture, enable_texture};
+use gl_types::{ShaderId, TextureId};
+use mesh::Mesh;
+
+#[deriving(Decodable)]
+struct GlyphDesc {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    originX: f32,
+    originY: f32,
+    advance: f32,
+}
+
+#[deriving(Decodable)]
+struct FontDesc {
+    width: f32,
+    height: f32,
+    size: f32,
+    characters: HashMap<~str, GlyphDesc>,
+}
+
+struct Glyph {
+    x: MFloat,
+    y: MFloat,
+    width: MFloat,
+    height: MFloat,
+    origin_x: MFloat,
+    origin_y: MFloat,
+    advance: MFloat,
+}
+
+/// A font baked ahead of time into a single texture atlas plus a JSON
+/// glyph table (`{width, height, size, characters: {"A": {x, y, ...}}}`).
+/// Unlike `FontStash`, which rasterizes glyphs at runtime, this just
+/// builds a textured quad mesh from the precomputed atlas coordinates --
+/// fast, and with no rasterizer dependency at all.
+pub struct BitmapFont {
+    texture: TextureId,
+    atlas_width: MFloat,
+    atlas_height: MFloat,
+    size: MFloat,
+    glyphs: HashMap<char, Glyph>,
+}
+
+impl BitmapFont {
+    pub fn new(texture_path: ~str, descriptor_path: &Path) -> BitmapFont {
+        let json_text = File::open(descriptor_path).read_to_str()
+            .unwrap_or_else(|msg| fail!("BitmapFont: read error: {}", msg));
+        let desc: FontDesc = json::decode(json_text.as_slice())
+            .unwrap_or_else(|msg| fail!("BitmapFont: bad glyph table: {}", msg));
+        let mut glyphs = HashMap::new();
+        for (key, g) in desc.characters.move_iter() {
+            let ch = key.as_slice().char_at(0);
+            glyphs.insert(ch, Glyph {
+                x: g.x,
+                y: g.y,
+                width: g.width,
+                height: g.height,
+                origin_x: g.originX,
+                origin_y: g.originY,
+                advance: g.advance,
+            });
+        }
+        BitmapFont {
+            texture: load_texture(texture_path),
+            atlas_width: desc.width,
+            atlas_height: desc.height,
+            size: desc.size,
+            glyphs: glyphs,
+        }
+    }
+
+    /// Walks a pen along `pos` and emits one textured quad per glyph,
+    /// advancing by each glyph's `advance` and resetting the pen on `\n`.
+    pub fn build_text_mesh(&self, text: &str, pos: Point2<MFloat>) -> (Vec<VertexCoord>, Vec<TextureCoord>) {
+        let mut positions = Vec::new();
+        let mut tex_coords = Vec::new();
+        let mut pen = pos.v;
+        for ch in text.chars() {
+            if ch == '\n' {
+                pen.x = pos.v.x;
+                pen.y += self.size;
+                continue;
+            }
+            let glyph = match self.glyphs.find(&ch) {
+                Some(glyph) => glyph,
+                None => continue,
+            };
+            let x0 = pen.x - glyph.origin_x;
+            let y0 = pen.y - glyph.origin_y;
+            let x1 = x0 + glyph.width;
+            let y1 = y0 + glyph.height;
+            positions.push(Vector3{x: x0, y: y0, z: 0.0});
+            positions.push(Vector3{x: x1, y: y0, z: 0.0});
+            positions.push(Vector3{x: x1, y: y1, z: 0.0});
+            positions.push(Vector3{x: x0, y: y0, z: 0.0});
+            positions.push(Vector3{x: x1, y: y1, z: 0.0});
+            positions.push(Vector3{x: x0, y: y1, z: 0.0});
+
+            // Flip V: the atlas JSON is top-left-origin but load_texture
+            // uploads rows top-down for bottom-up GL sampling, same as
+            // the `1.0 - y` flip in visualizer/obj.rs's read_vt.
+            let u0 = glyph.x / self.atlas_width;
+            let v0 = 1.0 - glyph.y / self.atlas_height;
+            let u1 = (glyph.x + glyph.width) / self.atlas_width;
+            let v1 = 1.0 - (glyph.y + glyph.height) / self.atlas_height;
+            tex_coords.push(Vector2{x: u0, y: v0});
+            tex_coords.push(Vector2{x: u1, y: v0});
+            tex_coords.push(Vector2{x: u1, y: v1});
+            tex_coords.push(Vector2{x: u0, y: v0});
+            tex_coords.push(Vector2{x: u1, y: v1});
+            tex_coords.push(Vector2{x: u0, y: v1});
+
+            pen.x += glyph.advance;
+        }
+        (positions, tex_coords)
+    }
+
+    /// Builds and draws a `Mesh` for `text` at `pos` in one shot.
+    pub fn draw_text(&self, program: &ShaderId, text: &str, pos: Point2<MFloat>) {
+        let (positions, tex_coords) = self.build_text_mesh(text, pos);
+        let mut mesh = Mesh::new();
+        mesh.init(positions.as_slice());
+        mesh.set_texture_coords(tex_coords.as_slice());
+        enable_texture(program, &self.texture);
+        let ShaderId(program_id) = *program;
+        mesh.draw(program_id);
+    }
+}
+
+// vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
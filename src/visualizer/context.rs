@@ -5,10 +5,38 @@ use glfw;
 use cgmath::vector::Vector2;
 use core::types::{Size2, MInt, Point2};
 use core::conf::Config;
+use core_types::{Size2 as GlSize2, Int as GlInt};
 use visualizer::types::{MFloat, MatId, ColorId};
 use visualizer::shader::Shader;
 use visualizer::font_stash::FontStash;
 use visualizer::mgl;
+use gl_helpers::{
+    Profiler,
+    Framebuffer,
+    ClampToEdge,
+};
+use gl_helpers;
+use gl_types::ShaderId;
+use misc::read_file;
+
+fn to_gl_size(size: Size2<MInt>) -> GlSize2<GlInt> {
+    GlSize2{w: size.w as GlInt, h: size.h as GlInt}
+}
+
+/// Compiles the FXAA resolve program. Called once by whatever builds the
+/// `Context`, and the result is stashed in `Context::fxaa_program`.
+pub fn compile_fxaa_program() -> ShaderId {
+    gl_helpers::compile_program(
+        read_file(&Path::new("fxaa.vs.glsl")),
+        read_file(&Path::new("fxaa.fs.glsl")),
+    )
+}
+
+/// Allocates the offscreen scene buffer the FXAA pass resolves from.
+/// Called once by whatever builds the `Context`.
+pub fn new_scene_buffer(win_size: Size2<MInt>) -> Framebuffer {
+    Framebuffer::new(to_gl_size(win_size), ClampToEdge)
+}
 
 pub struct Context {
     pub win: glfw::Window,
@@ -19,12 +47,21 @@ pub struct Context {
     pub shader: Shader,
     pub mvp_mat_id: MatId,
     pub basic_color_id: ColorId,
+    pub profiler: RefCell<Profiler>,
+    pub fxaa_enabled: bool, // set to false on weak GPUs to skip the FXAA resolve pass
+    scene_buffer: Framebuffer,
+    fxaa_program: ShaderId,
 }
 
 impl Context {
     fn set_window_size(&mut self, win_size: Size2<MInt>) {
         self.win_size = win_size;
         mgl::set_viewport(win_size);
+        self.scene_buffer.resize(to_gl_size(win_size));
+    }
+
+    pub fn toggle_fxaa(&mut self) {
+        self.fxaa_enabled = !self.fxaa_enabled;
     }
 
     pub fn handle_event(&mut self, event: glfw::WindowEvent) {
@@ -41,6 +78,42 @@ impl Context {
             _ => {},
         }
     }
+
+    /// Binds the offscreen scene buffer so the caller can render the
+    /// game into it. Must be paired with `resolve_fxaa` once the scene
+    /// is drawn.
+    pub fn begin_scene(&self) {
+        self.scene_buffer.bind();
+    }
+
+    /// Draws the offscreen scene buffer to the screen, running it
+    /// through the FXAA resolve shader unless `fxaa_enabled` is false
+    /// (e.g. on weak GPUs), in which case the scene is blitted through
+    /// untouched.
+    pub fn resolve_fxaa(&self) {
+        if self.fxaa_enabled {
+            gl_helpers::bind_default_framebuffer();
+            gl_helpers::use_program(&self.fxaa_program);
+            gl_helpers::enable_texture_as(&self.fxaa_program, self.scene_buffer.color_texture(), "source");
+            gl_helpers::set_size_uniform(&self.fxaa_program, "source_size", self.scene_buffer.size());
+            gl_helpers::draw_fullscreen_triangle();
+        } else {
+            self.scene_buffer.blit_to_default();
+        }
+    }
+
+    // Reads back last frame's GPU timings and draws them as a small
+    // text overlay, one "name: x.xx ms" line per profiled scope.
+    pub fn draw_profiler_overlay(&self) {
+        let scopes = self.profiler.borrow_mut().collect_and_advance();
+        let mut font_stash = self.font_stash.borrow_mut();
+        let line_height = 16.0;
+        for (i, &(ref name, ms)) in scopes.iter().enumerate() {
+            let text = format!("{}: {:.2} ms", *name, ms);
+            let pos = Point2{v: Vector2{x: 8.0, y: 8.0 + i as MFloat * line_height}};
+            font_stash.draw_text(text, pos);
+        }
+    }
 }
 
 // vim: set tabstop=4 shiftwidth=4 softtabstop=4 expandtab:
\ No newline at end of file
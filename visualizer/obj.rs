@@ -4,14 +4,46 @@ use std::str::Words;
 use std::str::CharSplits;
 use std::from_str::FromStr;
 use std::io::{BufferedReader, File};
+use std::collections::HashMap;
 use cgmath::vector::{Vector3, Vector2};
 use core::types::{MBool, MInt};
 use visualizer::types::{VertexCoord, TextureCoord, Normal};
 
+// Vertex, texture and normal indices for a single n-gon face. Indices are
+// 1-based (as in the .obj spec) and 0 means "absent" (no vt/vn given).
 struct Face {
-    vertex: [MInt, ..3],
-    texture: [MInt, ..3],
-    normal: [MInt, ..3],
+    vertex: Vec<MInt>,
+    texture: Vec<MInt>,
+    normal: Vec<MInt>,
+    material_id: uint,
+}
+
+struct Material {
+    name: ~str,
+    diffuse_color: Vector3<f32>,
+    diffuse_texture: Option<~str>,
+}
+
+impl Material {
+    fn new(name: ~str) -> Material {
+        Material {
+            name: name,
+            diffuse_color: Vector3{x: 1.0, y: 1.0, z: 1.0},
+            diffuse_texture: None,
+        }
+    }
+}
+
+/// All the triangles sharing a single material, ready to be uploaded
+/// to the GPU as one indexed draw call: `coords`/`normals`/`texture_coords`
+/// hold one entry per unique (vertex, texture, normal) triple, and
+/// `indices` are the triangle list into those deduplicated vertices.
+pub struct MaterialGroup {
+    pub material_name: Option<~str>,
+    pub coords: Vec<VertexCoord>,
+    pub normals: Vec<Normal>,
+    pub texture_coords: Vec<TextureCoord>,
+    pub indices: Vec<u32>,
 }
 
 pub struct Model {
@@ -19,6 +51,8 @@ pub struct Model {
     normals: Vec<Normal>,
     texture_coords: Vec<TextureCoord>,
     faces: Vec<Face>,
+    materials: Vec<Material>,
+    current_material_id: uint,
 }
 
 fn parse_word<T: FromStr>(words: &mut Words) -> T {
@@ -26,9 +60,20 @@ fn parse_word<T: FromStr>(words: &mut Words) -> T {
     from_str(str).expect("Can not convert from string")
 }
 
-fn parse_charsplit<T: FromStr>(words: &mut CharSplits<char>) -> T {
-    let str = words.next().expect("Can not read next word");
-    from_str(str).expect("Can not convert from string")
+// Parses a possibly empty `v`/`vt`/`vn` index field of a `v/vt/vn` face
+// group. An empty field means "absent" and is represented as 0. A
+// negative index is relative to the end of the list seen so far, as per
+// the .obj spec (`-1` is the last element added).
+fn parse_index_field(field: &str, count: uint) -> MInt {
+    if field.len() == 0 {
+        return 0;
+    }
+    let raw: MInt = from_str(field).expect("Can not parse face index");
+    if raw < 0 {
+        count as MInt + raw + 1
+    } else {
+        raw
+    }
 }
 
 impl Model {
@@ -38,6 +83,8 @@ impl Model {
             normals: Vec::new(),
             texture_coords: Vec::new(),
             faces: Vec::new(),
+            materials: vec!(Material::new("".to_owned())),
+            current_material_id: 0,
         };
         obj.read(path);
         obj
@@ -58,24 +105,70 @@ impl Model {
         }
     }
 
-    fn read_f(words: &mut Words) -> Face {
+    fn read_f(&mut self, words: &mut Words) -> Face {
         let mut face = Face {
-            vertex: [0, 0, 0],
-            texture: [0, 0, 0],
-            normal: [0, 0, 0],
+            vertex: Vec::new(),
+            texture: Vec::new(),
+            normal: Vec::new(),
+            material_id: self.current_material_id,
         };
-        let mut i = 0;
         for group in *words {
-            let mut w = group.split('/');
-            face.vertex[i] = parse_charsplit(&mut w);
-            face.texture[i] = parse_charsplit(&mut w);
-            face.normal[i] = parse_charsplit(&mut w);
-            i += 1;
+            let mut fields: CharSplits<char> = group.split('/');
+            let v = fields.next().unwrap_or("");
+            let vt = fields.next().unwrap_or("");
+            let vn = fields.next().unwrap_or("");
+            face.vertex.push(parse_index_field(v, self.coords.len()));
+            face.texture.push(parse_index_field(vt, self.texture_coords.len()));
+            face.normal.push(parse_index_field(vn, self.normals.len()));
         }
         face
     }
 
-    fn read_line(&mut self, line: &str) {
+    fn read_mtllib(&mut self, words: &mut Words, obj_path: &Path) {
+        let filename = words.next().expect("Can not read mtllib filename");
+        let mtl_path = obj_path.dir_path().join(filename);
+        self.read_mtl(&mtl_path);
+    }
+
+    fn read_mtl(&mut self, path: &Path) {
+        let mut file = BufferedReader::new(File::open(path));
+        for line in file.lines() {
+            let line = line.unwrap_or_else(|msg| fail!("Mtl: read error: {}", msg));
+            let mut words = line.as_slice().words();
+            match words.next() {
+                Some("newmtl") => {
+                    let name = words.next().expect("Can not read material name");
+                    self.materials.push(Material::new(name.to_owned()));
+                },
+                Some("Kd") => {
+                    let material = self.materials.mut_last()
+                        .expect("Kd before newmtl");
+                    material.diffuse_color = Vector3 {
+                        x: parse_word(&mut words),
+                        y: parse_word(&mut words),
+                        z: parse_word(&mut words),
+                    };
+                },
+                Some("map_Kd") => {
+                    let texture_path = words.next()
+                        .expect("Can not read map_Kd path");
+                    let material = self.materials.mut_last()
+                        .expect("map_Kd before newmtl");
+                    material.diffuse_texture = Some(texture_path.to_owned());
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn read_usemtl(&mut self, words: &mut Words) {
+        let name = words.next().expect("Can not read usemtl name");
+        self.current_material_id = self.materials.iter()
+            .position(|m| m.name.as_slice() == name)
+            .unwrap_or_else(|| fail!("Unknown material: {}", name));
+    }
+
+    fn read_line(&mut self, line: &str, obj_path: &Path) {
         let mut words = line.words();
         fn is_correct_tag(tag: &str) -> MBool {
             tag.len() != 0 && tag[0] != ('#' as u8)
@@ -87,7 +180,12 @@ impl Model {
                     &"v" => self.coords.push(Model::read_v_or_vn(w)),
                     &"vn" => self.normals.push(Model::read_v_or_vn(w)),
                     &"vt" => self.texture_coords.push(Model::read_vt(w)),
-                    &"f" => self.faces.push(Model::read_f(w)),
+                    &"f" => {
+                        let face = self.read_f(w);
+                        self.faces.push(face);
+                    },
+                    &"mtllib" => self.read_mtllib(w, obj_path),
+                    &"usemtl" => self.read_usemtl(w),
                     _ => {},
                 }
             }
@@ -99,32 +197,117 @@ impl Model {
         let mut file = BufferedReader::new(File::open(path));
         for line in file.lines() {
             match line {
-                Ok(line) => self.read_line(line),
+                Ok(line) => self.read_line(line.as_slice(), path),
                 Err(msg) => fail!("Obj: read error: {}", msg),
             }
         }
     }
 
-    pub fn build(&self) -> Vec<VertexCoord> {
-        let mut mesh = Vec::new();
+    // Fans an n-gon face (v0, v1, .., vk) into a list of triangles
+    // (v0, v1, v2), (v0, v2, v3), .., (v0, vk-1, vk).
+    fn triangle_indices(face_len: uint) -> Vec<[uint, ..3]> {
+        // A face needs at least 3 vertices to fan into any triangles; bail
+        // out before the `face_len - 1` below, since `face_len` is `uint`
+        // and a malformed `f` line with fewer than 2 vertices would
+        // underflow it into a near-uint::MAX loop bound instead of simply
+        // producing no triangles.
+        if face_len < 3 {
+            return Vec::new();
+        }
+        let mut triangles = Vec::new();
+        for i in range(1, face_len - 1) {
+            triangles.push([0, i, i + 1]);
+        }
+        triangles
+    }
+
+    // Per-vertex normals accumulated from triangles whose face did not
+    // specify `vn`: each triangle's cross-product is added to all three
+    // of its vertices, and the result is normalized on lookup.
+    fn synthesize_normals(&self) -> Vec<Normal> {
+        let mut acc: Vec<Normal> = Vec::from_elem(
+            self.coords.len(), Vector3{x: 0.0, y: 0.0, z: 0.0});
         for face in self.faces.iter() {
-            for i in range(0, 3) {
-                let vertex_id = face.vertex[i as uint] - 1;
-                mesh.push(*self.coords.get(vertex_id as uint));
+            if face.normal.iter().any(|&n| n != 0) {
+                continue;
+            }
+            for tri in Model::triangle_indices(face.vertex.len()).iter() {
+                let i0 = (face.vertex[tri[0]] - 1) as uint;
+                let i1 = (face.vertex[tri[1]] - 1) as uint;
+                let i2 = (face.vertex[tri[2]] - 1) as uint;
+                let p0 = *self.coords.get(i0);
+                let p1 = *self.coords.get(i1);
+                let p2 = *self.coords.get(i2);
+                let normal = (p1 - p0).cross(&(p2 - p0));
+                *acc.get_mut(i0) = *acc.get(i0) + normal;
+                *acc.get_mut(i1) = *acc.get(i1) + normal;
+                *acc.get_mut(i2) = *acc.get(i2) + normal;
             }
         }
-        mesh
+        for n in acc.mut_iter() {
+            *n = n.normalize();
+        }
+        acc
+    }
+
+    fn normal_at(&self, synthesized: &Vec<Normal>, vertex_id: MInt, normal_id: MInt) -> Normal {
+        if normal_id != 0 {
+            *self.normals.get((normal_id - 1) as uint)
+        } else {
+            *synthesized.get((vertex_id - 1) as uint)
+        }
     }
 
-    pub fn build_tex_coord(&self) -> Vec<TextureCoord> {
-        let mut tex_coords = Vec::new();
+    /// Builds one `MaterialGroup` per material used by the model,
+    /// triangulating any n-gon faces with a simple fan and deduplicating
+    /// (vertex, texture, normal) triples into a shared indexed vertex list.
+    pub fn build(&self) -> Vec<MaterialGroup> {
+        let synthesized_normals = self.synthesize_normals();
+        let mut groups: HashMap<uint, MaterialGroup> = HashMap::new();
+        let mut vertex_ids: HashMap<uint, HashMap<(MInt, MInt, MInt), u32>> = HashMap::new();
         for face in self.faces.iter() {
-            for i in range(0, 3) {
-                let texture_coord_id = face.texture[i as uint] as uint - 1;
-                tex_coords.push(*self.texture_coords.get(texture_coord_id));
+            let group = groups.find_or_insert_with(face.material_id, |_| {
+                let name = self.materials.get(face.material_id).name.clone();
+                MaterialGroup {
+                    material_name: if name.len() == 0 { None } else { Some(name) },
+                    coords: Vec::new(),
+                    normals: Vec::new(),
+                    texture_coords: Vec::new(),
+                    indices: Vec::new(),
+                }
+            });
+            let seen = vertex_ids.find_or_insert_with(face.material_id, |_| HashMap::new());
+            for tri in Model::triangle_indices(face.vertex.len()).iter() {
+                for &i in tri.iter() {
+                    let vertex_id = face.vertex[i];
+                    let texture_id = face.texture[i];
+                    let normal_id = face.normal[i];
+                    let key = (vertex_id, texture_id, normal_id);
+                    let index = match seen.find(&key) {
+                        Some(&index) => index,
+                        None => {
+                            let index = group.coords.len() as u32;
+                            group.coords.push(*self.coords.get((vertex_id - 1) as uint));
+                            group.normals.push(
+                                self.normal_at(&synthesized_normals, vertex_id, normal_id));
+                            // Keep texture_coords in lockstep with coords/normals/indices
+                            // (they're all addressed by the same index buffer): push a
+                            // placeholder when this face didn't give a `vt`, rather than
+                            // letting the array fall behind.
+                            group.texture_coords.push(if texture_id != 0 {
+                                *self.texture_coords.get((texture_id - 1) as uint)
+                            } else {
+                                Vector2{x: 0.0, y: 0.0}
+                            });
+                            seen.insert(key, index);
+                            index
+                        },
+                    };
+                    group.indices.push(index);
+                }
             }
         }
-        tex_coords
+        groups.move_iter().map(|(_, group)| group).collect()
     }
 }
 